@@ -1,26 +1,103 @@
 use anyhow::{Context, Error, Result};
+use async_stream::try_stream;
+use chrono::{DateTime, Utc};
+use futures::stream::Stream;
 use onefuzz::fs::{onefuzz_logs, onefuzz_root};
 use std::{
+    backtrace::BacktraceStatus,
+    collections::HashMap,
     fs,
+    io::{Read, Seek, SeekFrom},
     path::{Path, PathBuf},
+    time::Duration,
 };
 use uuid::Uuid;
 
+use crate::reboot::RebootContext;
+use crate::work::WorkSet;
+
 pub fn failure_path(machine_id: Uuid) -> Result<PathBuf> {
     Ok(onefuzz_root()?.join(format!("onefuzz-agent-failure-{}.txt", machine_id)))
 }
 
-pub fn save_failure(err: &Error, machine_id: Uuid) -> Result<()> {
+/// A structured crash report, capturing enough context to correlate
+/// repeated failures by task and machine without scraping free text.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FailureReport {
+    pub machine_id: Uuid,
+    pub timestamp: DateTime<Utc>,
+    pub agent_version: String,
+    /// The top-level error message.
+    pub error: String,
+    /// The full `anyhow` error chain, from the top-level error down to its
+    /// root cause.
+    pub chain: Vec<String>,
+    pub backtrace: Option<String>,
+    /// The `WorkSet` active at the time of failure, if a reboot context had
+    /// been saved.
+    pub work_set: Option<WorkSet>,
+}
+
+impl FailureReport {
+    pub fn new(err: &Error, machine_id: Uuid, reboot_context: Option<&RebootContext>) -> Self {
+        let chain: Vec<String> = err.chain().map(|cause| cause.to_string()).collect();
+        let error = err.to_string();
+
+        let backtrace = match err.backtrace().status() {
+            BacktraceStatus::Captured => Some(err.backtrace().to_string()),
+            _ => None,
+        };
+
+        Self {
+            machine_id,
+            timestamp: Utc::now(),
+            agent_version: env!("CARGO_PKG_VERSION").to_string(),
+            error,
+            chain,
+            backtrace,
+            work_set: reboot_context.map(|ctx| ctx.work_set.clone()),
+        }
+    }
+}
+
+/// The parsed contents of a failure report file: either the current
+/// structured form, or a bare message recovered from a legacy plain-text
+/// dump.
+pub enum FailureContent {
+    Report(FailureReport),
+    Legacy(String),
+}
+
+pub fn save_failure(
+    err: &Error,
+    machine_id: Uuid,
+    reboot_context: Option<&RebootContext>,
+) -> Result<()> {
     error!("saving failure: {:?}", err);
     let path = failure_path(machine_id)?;
-    let message = format!("{:?}", err);
-    fs::write(&path, message)
+    let report = FailureReport::new(err, machine_id, reboot_context);
+    write_report(&path, &report)
+}
+
+fn write_report(path: &Path, report: &FailureReport) -> Result<()> {
+    let data = serde_json::to_vec_pretty(report).context("unable to serialize failure report")?;
+    fs::write(path, data)
         .with_context(|| format!("unable to write failure log: {}", path.display()))
 }
 
-pub fn read_failure(machine_id: Uuid) -> Result<String> {
+pub fn read_failure(machine_id: Uuid) -> Result<FailureContent> {
     let path = failure_path(machine_id)?;
-    read_file_lossy(&path)
+    read_failure_at(&path)
+}
+
+fn read_failure_at(path: &Path) -> Result<FailureContent> {
+    let content = read_file_lossy(path)?;
+
+    match serde_json::from_str(&content) {
+        Ok(report) => Ok(FailureContent::Report(report)),
+        // Legacy failure logs are a bare `{:?}`-formatted error, not JSON.
+        Err(_) => Ok(FailureContent::Legacy(content)),
+    }
 }
 
 pub fn read_logs() -> Result<String> {
@@ -44,3 +121,132 @@ fn read_file_lossy(path: &Path) -> Result<String> {
         fs::read(path).with_context(|| format!("unable to read file: {}", path.display()))?;
     Ok(String::from_utf8_lossy(&content).to_string())
 }
+
+/// Default interval between polls of the logs directory for `follow_logs`.
+pub const DEFAULT_FOLLOW_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Stream newly-appended content from every file in the logs directory,
+/// `tail -f`-style, polling every `interval`.
+///
+/// File growth is detected by polling size rather than relying on an
+/// inotify/kqueue dependency, so this behaves identically on Windows and
+/// Unix. A file that shrinks (rotated or truncated) has its tracked offset
+/// reset to zero, and files created after the stream starts are picked up
+/// on the next poll.
+pub fn follow_logs(interval: Duration) -> impl Stream<Item = Result<String>> {
+    try_stream! {
+        let mut offsets: HashMap<PathBuf, u64> = HashMap::new();
+
+        loop {
+            let log_path = onefuzz_logs()?;
+
+            let entries = fs::read_dir(&log_path).with_context(|| {
+                format!("unable to read logs directory: {}", log_path.display())
+            })?;
+
+            for entry in entries {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(err) => {
+                        warn!("unable to read log directory entry: {:?}", err);
+                        continue;
+                    }
+                };
+                let path = entry.path();
+
+                // A file can legitimately disappear or get replaced between the
+                // directory listing above and the read below (log rotation
+                // racing this poll); don't let that kill the whole stream.
+                match poll_log_file(&path, &mut offsets) {
+                    Ok(Some(content)) => yield content,
+                    Ok(None) => {}
+                    Err(err) => warn!("unable to read log file {}: {:?}", path.display(), err),
+                }
+            }
+
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
+
+fn poll_log_file(path: &Path, offsets: &mut HashMap<PathBuf, u64>) -> Result<Option<String>> {
+    if !path.is_file() {
+        return Ok(None);
+    }
+
+    let len = fs::metadata(path)
+        .with_context(|| format!("unable to stat log file: {}", path.display()))?
+        .len();
+
+    let mut offset = *offsets.get(path).unwrap_or(&0);
+    if len < offset {
+        // The file shrank: treat it as rotated or truncated and start
+        // reading from the beginning again.
+        offset = 0;
+    }
+
+    if len <= offset {
+        offsets.insert(path.to_path_buf(), offset);
+        return Ok(None);
+    }
+
+    let mut file = fs::File::open(path)
+        .with_context(|| format!("unable to open log file: {}", path.display()))?;
+    file.seek(SeekFrom::Start(offset))
+        .with_context(|| format!("unable to seek log file: {}", path.display()))?;
+
+    let mut buf = Vec::with_capacity((len - offset) as usize);
+    file.read_to_end(&mut buf)
+        .with_context(|| format!("unable to read log file: {}", path.display()))?;
+
+    offsets.insert(path.to_path_buf(), offset + buf.len() as u64);
+
+    Ok(Some(format!(
+        "{}\n\n{}",
+        path.display(),
+        String::from_utf8_lossy(&buf)
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_and_read_round_trips_through_a_structured_report() {
+        let dir = tempfile::tempdir().expect("unable to create temp dir");
+        let path = dir.path().join("failure.json");
+
+        let err = anyhow::anyhow!("root cause").context("top level");
+        let report = FailureReport::new(&err, Uuid::new_v4(), None);
+        write_report(&path, &report).expect("unable to write failure report");
+
+        match read_failure_at(&path).expect("unable to read failure") {
+            FailureContent::Report(read) => {
+                assert_eq!(read.machine_id, report.machine_id);
+                assert_eq!(read.error, report.error);
+                assert_eq!(read.chain, report.chain);
+            }
+            FailureContent::Legacy(content) => {
+                panic!(
+                    "expected a structured report, got legacy content: {}",
+                    content
+                )
+            }
+        }
+    }
+
+    #[test]
+    fn legacy_plain_text_failures_are_read_as_legacy() {
+        let dir = tempfile::tempdir().expect("unable to create temp dir");
+        let path = dir.path().join("failure.txt");
+
+        let message = "Error: something went wrong\n  caused by: root cause";
+        fs::write(&path, message).expect("unable to write legacy failure");
+
+        match read_failure_at(&path).expect("unable to read failure") {
+            FailureContent::Legacy(content) => assert_eq!(content, message),
+            FailureContent::Report(_) => panic!("expected legacy content, got a structured report"),
+        }
+    }
+}
@@ -1,10 +1,12 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
-use std::path::PathBuf;
+use std::fmt;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
 use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
 use downcast_rs::Downcast;
 use tokio::fs;
 use uuid::Uuid;
@@ -17,7 +19,7 @@ pub trait IReboot: Downcast {
 
     async fn load_context(&self) -> Result<Option<RebootContext>>;
 
-    fn invoke(&self) -> Result<()>;
+    fn invoke(&self, reason: RebootReason) -> Result<()>;
 }
 
 impl_downcast!(IReboot);
@@ -32,24 +34,155 @@ impl IReboot for Reboot {
         self.load_context().await
     }
 
-    fn invoke(&self) -> Result<()> {
-        self.invoke()
+    fn invoke(&self, reason: RebootReason) -> Result<()> {
+        self.invoke(reason)
     }
 }
 
+/// The reason a node was rebooted, recorded alongside the `RebootContext` so
+/// operators can see why a node restarted without having to reconstruct it
+/// from logs.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum RebootReason {
+    /// A task explicitly requested the reboot (e.g. as a setup step).
+    TaskRequested,
+    /// The task's setup script requested the reboot.
+    SetupScript,
+    /// The agent updated itself and needs to restart to pick up the update.
+    AgentUpdate,
+    /// The watchdog detected an unresponsive node and forced a reboot.
+    Watchdog,
+    /// The reboot was requested without a more specific reason.
+    Unknown,
+}
+
+impl fmt::Display for RebootReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            RebootReason::TaskRequested => "task requested",
+            RebootReason::SetupScript => "setup script requested",
+            RebootReason::AgentUpdate => "agent update",
+            RebootReason::Watchdog => "watchdog",
+            RebootReason::Unknown => "unknown",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A host-supplied callback invoked in place of the real OS reboot command.
+///
+/// This lets unit tests assert which `RebootReason` triggered a reboot
+/// without actually rebooting, and lets platforms override the command used.
+pub type RebootCommand = Box<dyn Fn(RebootReason) -> Result<()> + Send + Sync>;
+
+/// Default cap on consecutive reboot attempts for the same `WorkSet` before
+/// a reboot is refused as a likely boot loop.
+pub const DEFAULT_MAX_REBOOT_ATTEMPTS: u32 = 5;
+
 pub struct Reboot {
     machine_id: Uuid,
+    command: RebootCommand,
+    max_reboot_attempts: u32,
+    root: Option<PathBuf>,
 }
 
 impl Reboot {
     pub fn new(machine_id: Uuid) -> Self {
-        Self { machine_id }
+        Self {
+            machine_id,
+            command: Box::new(local_reboot_command),
+            max_reboot_attempts: DEFAULT_MAX_REBOOT_ATTEMPTS,
+            root: None,
+        }
+    }
+
+    /// Construct a `Reboot` that invokes `command` instead of the platform's
+    /// real reboot command.
+    pub fn with_command(machine_id: Uuid, command: RebootCommand) -> Self {
+        Self {
+            machine_id,
+            command,
+            max_reboot_attempts: DEFAULT_MAX_REBOOT_ATTEMPTS,
+            root: None,
+        }
     }
 
-    pub async fn save_context(&self, ctx: RebootContext) -> Result<()> {
-        let path = reboot_context_path(self.machine_id)?;
+    /// Override the number of consecutive reboot attempts, for the same
+    /// `WorkSet`, allowed before a reboot loop is reported.
+    pub fn with_max_reboot_attempts(mut self, max_reboot_attempts: u32) -> Self {
+        self.max_reboot_attempts = max_reboot_attempts;
+        self
+    }
+
+    /// Override the directory `save_context`/`load_context` read and write
+    /// to, instead of `onefuzz::fs::onefuzz_root()`. Intended for tests, so
+    /// they don't depend on a real, fixed OS path being present and
+    /// writable.
+    pub fn with_root(mut self, root: PathBuf) -> Self {
+        self.root = Some(root);
+        self
+    }
 
-        info!("saving reboot context to: {}", path.display());
+    fn root(&self) -> Result<PathBuf> {
+        match &self.root {
+            Some(root) => Ok(root.clone()),
+            None => onefuzz::fs::onefuzz_root(),
+        }
+    }
+
+    fn reboot_context_path(&self) -> Result<PathBuf> {
+        Ok(self
+            .root()?
+            .join(format!("reboot_context_{}.json", self.machine_id)))
+    }
+
+    fn reboot_attempts_path(&self) -> Result<PathBuf> {
+        Ok(self
+            .root()?
+            .join(format!("reboot_attempts_{}.json", self.machine_id)))
+    }
+
+    pub async fn save_context(&self, mut ctx: RebootContext) -> Result<()> {
+        let path = self.reboot_context_path()?;
+        let attempts_path = self.reboot_attempts_path()?;
+
+        // The reboot context file itself does not survive a reboot attempt:
+        // `load_context` deletes it as soon as it is consumed on the next
+        // boot. Track the attempt count in a separate file so it survives
+        // that consumption and keeps counting across real reboots, not just
+        // repeated `save_context` calls within a single process.
+        match read_attempts(&attempts_path).await? {
+            Some(attempts) if attempts.work_set == ctx.work_set => {
+                ctx.reboot_attempts = attempts.reboot_attempts + 1;
+                ctx.first_attempt = attempts.first_attempt;
+            }
+            _ => {
+                ctx.reboot_attempts = 1;
+                ctx.first_attempt = Utc::now();
+            }
+        }
+
+        info!(
+            "saving reboot context to: {} (reason: {}, attempt {})",
+            path.display(),
+            ctx.reason,
+            ctx.reboot_attempts
+        );
+
+        let attempts = RebootAttempts {
+            work_set: ctx.work_set.clone(),
+            reboot_attempts: ctx.reboot_attempts,
+            first_attempt: ctx.first_attempt,
+        };
+        let attempts_data = serde_json::to_vec(&attempts)?;
+        fs::write(&attempts_path, &attempts_data)
+            .await
+            .with_context(|| {
+                format!(
+                    "unable to save reboot attempts: {}",
+                    attempts_path.display()
+                )
+            })?;
 
         let data = serde_json::to_vec(&ctx)?;
         fs::write(&path, &data)
@@ -62,55 +195,54 @@ impl Reboot {
     }
 
     pub async fn load_context(&self) -> Result<Option<RebootContext>> {
-        use std::io::ErrorKind;
-        let path = reboot_context_path(self.machine_id)?;
+        let path = self.reboot_context_path()?;
 
         info!("checking for saved reboot context: {}", path.display());
 
-        let data = fs::read(&path).await;
-
-        if let Err(err) = &data {
-            if let ErrorKind::NotFound = err.kind() {
+        let ctx = match read_context(&path).await? {
+            Some(ctx) => ctx,
+            None => {
                 // If new image, there won't be any reboot context.
                 info!("no reboot context found");
                 return Ok(None);
             }
-        }
-
-        let data = data?;
-        let ctx = serde_json::from_slice(&data)?;
+        };
 
         fs::remove_file(&path)
             .await
             .with_context(|| format!("unable to remove reboot context: {}", path.display()))?;
 
+        if ctx.reboot_attempts > self.max_reboot_attempts {
+            let elapsed = Utc::now() - ctx.first_attempt;
+            error!(
+                "reboot loop detected: {} attempts over {} seconds",
+                ctx.reboot_attempts,
+                elapsed.num_seconds()
+            );
+
+            // The loop is over either way: don't let a stale attempt count
+            // carry over to a later reboot of the same `WorkSet`.
+            let attempts_path = self.reboot_attempts_path()?;
+            remove_if_exists(&attempts_path).await?;
+
+            return Err(RebootLoopDetected {
+                attempts: ctx.reboot_attempts,
+                elapsed,
+            }
+            .into());
+        }
+
         info!("loaded reboot context");
         Ok(Some(ctx))
     }
 
-    #[cfg(target_family = "unix")]
-    pub fn invoke(&self) -> Result<()> {
-        info!("invoking local reboot command");
-
-        Command::new("reboot").arg("-f").status()?;
+    pub fn invoke(&self, reason: RebootReason) -> Result<()> {
+        info!("invoking reboot command (reason: {})", reason);
 
-        self.wait_for_reboot()
+        (self.command)(reason)
     }
 
-    #[cfg(target_family = "windows")]
-    pub fn invoke(&self) -> Result<()> {
-        info!("invoking local reboot command");
-
-        Command::new("powershell.exe")
-            .arg("-Command")
-            .arg("Restart-Computer")
-            .arg("-Force")
-            .status()?;
-
-        self.wait_for_reboot()
-    }
-
-    fn wait_for_reboot(&self) -> Result<()> {
+    fn wait_for_reboot() -> Result<()> {
         use std::{thread, time};
 
         debug!("waiting for reboot");
@@ -123,20 +255,192 @@ impl Reboot {
     }
 }
 
+#[cfg(target_family = "unix")]
+fn local_reboot_command(_reason: RebootReason) -> Result<()> {
+    Command::new("reboot").arg("-f").status()?;
+
+    Reboot::wait_for_reboot()
+}
+
+#[cfg(target_family = "windows")]
+fn local_reboot_command(_reason: RebootReason) -> Result<()> {
+    Command::new("powershell.exe")
+        .arg("-Command")
+        .arg("Restart-Computer")
+        .arg("-Force")
+        .status()?;
+
+    Reboot::wait_for_reboot()
+}
+
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct RebootContext {
     pub work_set: WorkSet,
+    pub reason: RebootReason,
+    pub reboot_attempts: u32,
+    pub first_attempt: DateTime<Utc>,
 }
 
 impl RebootContext {
-    pub fn new(work_set: WorkSet) -> Self {
-        Self { work_set }
+    pub fn new(work_set: WorkSet, reason: RebootReason) -> Self {
+        Self {
+            work_set,
+            reason,
+            reboot_attempts: 1,
+            first_attempt: Utc::now(),
+        }
+    }
+}
+
+/// Returned by `Reboot::load_context` when a `WorkSet` has exceeded its
+/// allowed reboot attempts, so the agent can report a clear failure instead
+/// of rebooting into the same loop again.
+#[derive(Debug)]
+pub struct RebootLoopDetected {
+    pub attempts: u32,
+    pub elapsed: chrono::Duration,
+}
+
+impl fmt::Display for RebootLoopDetected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "reboot loop detected: {} attempts over {} seconds",
+            self.attempts,
+            self.elapsed.num_seconds()
+        )
+    }
+}
+
+impl std::error::Error for RebootLoopDetected {}
+
+/// The attempt count for a `WorkSet`'s reboot, persisted separately from
+/// `RebootContext` so it survives `load_context` consuming and deleting the
+/// context file on every boot.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct RebootAttempts {
+    work_set: WorkSet,
+    reboot_attempts: u32,
+    first_attempt: DateTime<Utc>,
+}
+
+async fn read_context(path: &Path) -> Result<Option<RebootContext>> {
+    use std::io::ErrorKind;
+
+    let data = fs::read(path).await;
+
+    if let Err(err) = &data {
+        if let ErrorKind::NotFound = err.kind() {
+            return Ok(None);
+        }
+    }
+
+    let data = data?;
+    let ctx = serde_json::from_slice(&data)?;
+    Ok(Some(ctx))
+}
+
+async fn read_attempts(path: &Path) -> Result<Option<RebootAttempts>> {
+    use std::io::ErrorKind;
+
+    let data = fs::read(path).await;
+
+    if let Err(err) = &data {
+        if let ErrorKind::NotFound = err.kind() {
+            return Ok(None);
+        }
     }
+
+    let data = data?;
+    let attempts = serde_json::from_slice(&data)?;
+    Ok(Some(attempts))
 }
 
-fn reboot_context_path(machine_id: Uuid) -> Result<PathBuf> {
-    Ok(onefuzz::fs::onefuzz_root()?.join(format!("reboot_context_{}.json", machine_id)))
+async fn remove_if_exists(path: &Path) -> Result<()> {
+    use std::io::ErrorKind;
+
+    if let Err(err) = fs::remove_file(path).await {
+        if err.kind() != ErrorKind::NotFound {
+            return Err(err).with_context(|| format!("unable to remove {}", path.display()));
+        }
+    }
+
+    Ok(())
 }
 
 #[cfg(test)]
 pub mod double;
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[test]
+    fn invoke_threads_the_reason_through_to_the_injected_command() {
+        let seen_reason = Arc::new(Mutex::new(None));
+        let captured = Arc::clone(&seen_reason);
+
+        let reboot = Reboot::with_command(
+            Uuid::new_v4(),
+            Box::new(move |reason| {
+                *captured.lock().unwrap() = Some(reason);
+                Ok(())
+            }),
+        );
+
+        reboot
+            .invoke(RebootReason::AgentUpdate)
+            .expect("invoke should call the injected command");
+
+        assert_eq!(
+            *seen_reason.lock().unwrap(),
+            Some(RebootReason::AgentUpdate)
+        );
+    }
+
+    fn test_work_set() -> WorkSet {
+        WorkSet {
+            reboot: true,
+            setup_url: "https://example.com/setup".parse().unwrap(),
+            script: false,
+            work_units: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn reboot_loop_is_detected_across_repeated_boots() {
+        let root = tempfile::tempdir().expect("unable to create temp dir");
+        let reboot = Reboot::new(Uuid::new_v4())
+            .with_root(root.path().to_path_buf())
+            .with_max_reboot_attempts(3);
+        let work_set = test_work_set();
+
+        // Each iteration models one boot: the agent saves a context, "reboots",
+        // then loads the context back on the next boot.
+        for _ in 0..3 {
+            reboot
+                .save_context(RebootContext::new(work_set.clone(), RebootReason::Watchdog))
+                .await
+                .expect("save_context should succeed while under the attempt cap");
+
+            reboot
+                .load_context()
+                .await
+                .expect("load_context should succeed while under the attempt cap");
+        }
+
+        reboot
+            .save_context(RebootContext::new(work_set.clone(), RebootReason::Watchdog))
+            .await
+            .expect("save_context should succeed even past the attempt cap");
+
+        let err = reboot
+            .load_context()
+            .await
+            .expect_err("load_context should refuse to replay a looping reboot");
+
+        assert!(err.downcast_ref::<RebootLoopDetected>().is_some());
+    }
+}
@@ -0,0 +1,258 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+
+use crate::reboot::{IReboot, RebootContext};
+
+/// Outcome of attempting to commit a pending reboot.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CommitAction {
+    /// A pending reboot was committed and the reboot command was invoked.
+    Reboot,
+    /// There was no pending reboot, or it is still blocked by an open guard.
+    None,
+}
+
+/// Explicit signals a caller can send to the controller outside of the
+/// lifetime of a `RebootGuard`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ControlRequest {
+    /// Release a pending reboot immediately, as if all guards had dropped.
+    Unblock,
+}
+
+struct State {
+    pending: Option<RebootContext>,
+    guards: usize,
+}
+
+/// Coordinates between in-flight work and `Reboot::invoke`, so that a reboot
+/// requested while a task is running does not kill that task mid-flight.
+///
+/// Work that must not be interrupted holds a `RebootGuard` obtained from
+/// `guard()`. While any guard is alive, a requested reboot stays pending;
+/// once the last guard drops (or a caller sends `ControlRequest::Unblock`),
+/// the controller persists the `RebootContext` and invokes the reboot.
+pub struct RebootController {
+    reboot: Arc<dyn IReboot>,
+    state: Mutex<State>,
+}
+
+impl RebootController {
+    pub fn new(reboot: Arc<dyn IReboot>) -> Self {
+        Self {
+            reboot,
+            state: Mutex::new(State {
+                pending: None,
+                guards: 0,
+            }),
+        }
+    }
+
+    /// Request a reboot. This does not reboot directly: it records the
+    /// context and moves the controller to a "reboot pending" state. If no
+    /// guards are currently held, the reboot is committed immediately;
+    /// otherwise it is committed once the last guard drops (or a caller
+    /// sends `ControlRequest::Unblock`).
+    pub async fn request(&self, ctx: RebootContext) -> Result<CommitAction> {
+        info!("reboot requested, reason: {}", ctx.reason);
+
+        {
+            let mut state = self.state.lock().unwrap();
+            state.pending = Some(ctx);
+        }
+
+        self.try_commit().await
+    }
+
+    /// Obtain an RAII guard that blocks a pending reboot for as long as it
+    /// is held.
+    pub fn guard(self: &Arc<Self>) -> RebootGuard {
+        let mut state = self.state.lock().unwrap();
+        state.guards += 1;
+
+        RebootGuard {
+            controller: Arc::clone(self),
+        }
+    }
+
+    /// Handle an explicit control signal from a caller.
+    pub async fn control(&self, request: ControlRequest) -> Result<CommitAction> {
+        match request {
+            ControlRequest::Unblock => {
+                let mut state = self.state.lock().unwrap();
+                state.guards = 0;
+                drop(state);
+
+                self.try_commit().await
+            }
+        }
+    }
+
+    async fn release(&self) -> Result<CommitAction> {
+        let remaining = {
+            let mut state = self.state.lock().unwrap();
+            state.guards = state.guards.saturating_sub(1);
+            state.guards
+        };
+
+        if remaining == 0 {
+            self.try_commit().await
+        } else {
+            Ok(CommitAction::None)
+        }
+    }
+
+    async fn try_commit(&self) -> Result<CommitAction> {
+        let ctx = {
+            let mut state = self.state.lock().unwrap();
+            if state.guards > 0 {
+                return Ok(CommitAction::None);
+            }
+            state.pending.take()
+        };
+
+        let ctx = match ctx {
+            Some(ctx) => ctx,
+            None => return Ok(CommitAction::None),
+        };
+
+        let reason = ctx.reason;
+        self.reboot.save_context(ctx).await?;
+        self.reboot.invoke(reason)?;
+
+        Ok(CommitAction::Reboot)
+    }
+}
+
+/// An RAII token that blocks a pending reboot for as long as it is held.
+/// Dropping the last outstanding guard releases the pending reboot, if any.
+pub struct RebootGuard {
+    controller: Arc<RebootController>,
+}
+
+impl Drop for RebootGuard {
+    fn drop(&mut self) {
+        let controller = Arc::clone(&self.controller);
+
+        tokio::spawn(async move {
+            if let Err(err) = controller.release().await {
+                error!("error releasing reboot guard: {:?}", err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+    use crate::reboot::{RebootContext, RebootReason};
+    use crate::work::WorkSet;
+
+    fn test_work_set() -> WorkSet {
+        WorkSet {
+            reboot: true,
+            setup_url: "https://example.com/setup".parse().unwrap(),
+            script: false,
+            work_units: vec![],
+        }
+    }
+
+    struct MockReboot {
+        invocations: AtomicUsize,
+    }
+
+    impl MockReboot {
+        fn new() -> Self {
+            Self {
+                invocations: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl IReboot for MockReboot {
+        async fn save_context(&self, _ctx: RebootContext) -> Result<()> {
+            Ok(())
+        }
+
+        async fn load_context(&self) -> Result<Option<RebootContext>> {
+            Ok(None)
+        }
+
+        fn invoke(&self, _reason: RebootReason) -> Result<()> {
+            self.invocations.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn request_with_no_guards_commits_immediately() {
+        let reboot = Arc::new(MockReboot::new());
+        let controller = RebootController::new(reboot.clone());
+
+        let action = controller
+            .request(RebootContext::new(test_work_set(), RebootReason::Watchdog))
+            .await
+            .expect("request should succeed");
+
+        assert_eq!(action, CommitAction::Reboot);
+        assert_eq!(reboot.invocations.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn guard_blocks_commit_until_dropped() {
+        let reboot = Arc::new(MockReboot::new());
+        let controller = Arc::new(RebootController::new(reboot.clone()));
+
+        let guard = controller.guard();
+
+        let action = controller
+            .request(RebootContext::new(test_work_set(), RebootReason::Watchdog))
+            .await
+            .expect("request should succeed");
+
+        assert_eq!(action, CommitAction::None);
+        assert_eq!(reboot.invocations.load(Ordering::SeqCst), 0);
+
+        drop(guard);
+
+        // The guard releases the pending reboot on a spawned task; give it a
+        // chance to run.
+        for _ in 0..50 {
+            if reboot.invocations.load(Ordering::SeqCst) > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(reboot.invocations.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn unblock_commits_pending_reboot_despite_open_guard() {
+        let reboot = Arc::new(MockReboot::new());
+        let controller = Arc::new(RebootController::new(reboot.clone()));
+
+        let _guard = controller.guard();
+
+        controller
+            .request(RebootContext::new(test_work_set(), RebootReason::Watchdog))
+            .await
+            .expect("request should succeed");
+
+        let action = controller
+            .control(ControlRequest::Unblock)
+            .await
+            .expect("control should succeed");
+
+        assert_eq!(action, CommitAction::Reboot);
+        assert_eq!(reboot.invocations.load(Ordering::SeqCst), 1);
+    }
+}